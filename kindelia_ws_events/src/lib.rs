@@ -1,61 +1,521 @@
+use async_trait::async_trait;
 use futures_util::{SinkExt, StreamExt};
-use tokio::sync::broadcast;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
 use warp::ws::{Message, WebSocket};
 use warp::{Filter, Rejection, Reply};
 
+use std::collections::{HashMap, VecDeque};
 use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 /// Main trait of the lib.
-/// 
+///
 /// Specifies a enum struct that must be serializable by serde
-/// and that must have a `Discriminant`, a type which is used 
+/// and that must have a `Discriminant`, a type which is used
 /// to convert between filtrable tags in string to the actual item enum type
 pub trait SerializableWithDiscriminant
 where
   Self: Send + Clone + serde::Serialize,
 {
-  type Discriminant: Send + From<Self> + FromStr<Err = String> + Eq;
+  type Discriminant: Send + From<Self> + FromStr<Err = String> + Eq + std::hash::Hash;
+}
+
+/// Handles client-issued requests sent over an already-open connection,
+/// as opposed to the broadcast items pushed to every subscriber.
+///
+/// Each incoming frame (text for JSON, binary for the binary encodings)
+/// is decoded into `Req` and answered with a
+/// `Resp`, which `client_connection` wraps in an envelope carrying the
+/// same `request_id` the client sent, so the reply can be matched to the
+/// call that produced it. `Ctx` carries whatever shared state the
+/// handler needs (e.g. a handle to the node) and is cloned for each
+/// request handed to `handle`.
+#[async_trait]
+pub trait RequestHandler: Send + Sync + 'static {
+  type Req: DeserializeOwned + Send;
+  type Resp: Serialize + Send;
+  type Ctx: Clone + Send + Sync + 'static;
+
+  async fn handle(&self, ctx: Self::Ctx, req: Self::Req) -> Self::Resp;
 }
 
 #[derive(serde::Deserialize)]
 struct QueryParams {
   tags: Option<String>,
+  encoding: Option<String>,
+  replay: Option<usize>,
 }
 
 struct Query {
   tags: Vec<String>,
+  encoding: Encoding,
+  /// Number of matching historical items to replay before switching to
+  /// the live stream. Zero (the default) means no replay.
+  replay: usize,
+}
+
+/// Wire format used for both the broadcast/response frames the server
+/// sends and the request/control frames a client sends back. Chosen
+/// per-connection via the `encoding` query parameter; defaults to JSON.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+  Json,
+  MessagePack,
+  Cbor,
+}
+
+impl Default for Encoding {
+  fn default() -> Self {
+    Encoding::Json
+  }
+}
+
+impl FromStr for Encoding {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "json" => Ok(Encoding::Json),
+      "msgpack" | "messagepack" => Ok(Encoding::MessagePack),
+      "cbor" => Ok(Encoding::Cbor),
+      other => Err(format!("unknown encoding: {}", other)),
+    }
+  }
+}
+
+/// Serializes `value` under `encoding`, producing a text frame for JSON
+/// and a binary frame for the binary formats.
+fn encode_message<S: Serialize>(
+  encoding: Encoding,
+  value: &S,
+) -> Result<Message, String> {
+  match encoding {
+    Encoding::Json => {
+      serde_json::to_string(value).map(Message::text).map_err(|err| err.to_string())
+    }
+    Encoding::MessagePack => {
+      // `serde(flatten)` and internally-tagged enums serialize via a map
+      // of unknown length, which rmp-serde's length-prefixed maps can't
+      // represent directly and rejects outright. Bouncing through
+      // `serde_json::Value` first collapses that into a map of known
+      // length (its `Map` is already concrete) before handing it off.
+      serde_json::to_value(value)
+        .map_err(|err| err.to_string())
+        .and_then(|value| rmp_serde::to_vec(&value).map_err(|err| err.to_string()))
+        .map(Message::binary)
+    }
+    Encoding::Cbor => {
+      let mut buf = Vec::new();
+      ciborium::ser::into_writer(value, &mut buf).map_err(|err| err.to_string())?;
+      Ok(Message::binary(buf))
+    }
+  }
+}
+
+/// Deserializes `msg` under `encoding`. Returns `None` on any decode
+/// failure, including a frame of the wrong kind (e.g. text under a
+/// binary encoding).
+fn decode_message<D: DeserializeOwned>(encoding: Encoding, msg: &Message) -> Option<D> {
+  match encoding {
+    Encoding::Json => serde_json::from_str(msg.to_str().ok()?).ok(),
+    Encoding::MessagePack => rmp_serde::from_slice(msg.as_bytes()).ok(),
+    Encoding::Cbor => ciborium::de::from_reader(msg.as_bytes()).ok(),
+  }
+}
+
+/// A client-issued request, tagged with a `request_id` chosen by the
+/// client so the matching response can be correlated on their end.
+#[derive(serde::Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize, PartialEq, Debug))]
+struct RequestEnvelope<Req> {
+  request_id: String,
+  #[serde(flatten)]
+  req: Req,
+}
+
+/// The reply to a `RequestEnvelope`, echoing back its `request_id`.
+#[derive(serde::Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize, PartialEq, Debug))]
+struct ResponseEnvelope<Resp> {
+  request_id: String,
+  #[serde(flatten)]
+  resp: Resp,
+}
+
+/// A control frame a client can send to change its own tag subscription
+/// after the connection is already open, instead of only at connect
+/// time via the `tags` query parameter.
+#[derive(serde::Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize, PartialEq, Debug))]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum ControlMessage {
+  Subscribe { tags: Vec<String> },
+  Unsubscribe { tags: Vec<String> },
+}
+
+/// An out-of-band notice from the server, distinct from both broadcast
+/// items and request responses.
+#[derive(serde::Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize, PartialEq, Debug))]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerNotice {
+  Error { message: String },
+  Lagged { skipped: u64 },
+}
+
+/// Configures the bounded local send buffer that sits between the
+/// broadcast receiver and the client's websocket, so that a slow client
+/// applies backpressure to itself rather than blocking delivery to
+/// other clients or the shared `broadcast::Sender`.
+#[derive(Clone, Copy)]
+pub struct SendBufferConfig {
+  /// Number of outgoing messages queued for a client. A message that
+  /// arrives while the buffer is full is dropped, not queued.
+  pub capacity: usize,
+  /// Number of consecutive attempts to enqueue into a full buffer after
+  /// which the connection is closed.
+  pub max_full_polls: usize,
+}
+
+impl Default for SendBufferConfig {
+  fn default() -> Self {
+    SendBufferConfig { capacity: 32, max_full_polls: 10 }
+  }
+}
+
+/// Tries to enqueue `msg` onto the client's outbox without blocking.
+/// Returns `false` once the connection should be closed: either the
+/// outbox has been full for `max_full_polls` consecutive attempts, or
+/// the writer task behind it has already gone away.
+fn enqueue(
+  outbox: &mpsc::Sender<Message>,
+  full_count: &mut usize,
+  max_full_polls: usize,
+  msg: Message,
+) -> bool {
+  match outbox.try_send(msg) {
+    Ok(()) => {
+      *full_count = 0;
+      true
+    }
+    Err(mpsc::error::TrySendError::Full(_)) => {
+      *full_count += 1;
+      *full_count < max_full_polls
+    }
+    Err(mpsc::error::TrySendError::Closed(_)) => false,
+  }
+}
+
+/// The tag filter applied to broadcast items for a connection. Kept as
+/// its own type rather than a `Vec` that's empty-means-everything,
+/// because a client that subscribed to specific tags and then
+/// unsubscribed all of them should end up matching nothing, not
+/// silently falling back to the full firehose.
+enum TagFilter<D> {
+  All,
+  Only(Vec<D>),
+}
+
+impl<D: Eq> TagFilter<D> {
+  fn matches(&self, discriminant: &D) -> bool {
+    match self {
+      TagFilter::All => true,
+      TagFilter::Only(tags) => tags.contains(discriminant),
+    }
+  }
+
+  /// Adding tags to `All` is a no-op: there's nothing narrower than
+  /// everything to add to.
+  fn subscribe(&mut self, tags: Vec<D>) {
+    if let TagFilter::Only(active) = self {
+      for tag in tags {
+        if !active.contains(&tag) {
+          active.push(tag);
+        }
+      }
+    }
+  }
+
+  /// Removing tags from `All` is also a no-op, matching the previous
+  /// behavior where an empty tag list meant "everything" and couldn't
+  /// be narrowed by unsubscribing.
+  fn unsubscribe(&mut self, tags: &[D]) {
+    if let TagFilter::Only(active) = self {
+      active.retain(|tag| !tags.contains(tag));
+    }
+  }
+}
+
+/// Applies a `ControlMessage` to the connection's active tag set. An
+/// unknown tag is reported back to the caller as an error instead of
+/// closing the connection; none of the batch's tags are applied then.
+fn apply_control_message<T: SerializableWithDiscriminant>(
+  msg: ControlMessage,
+  active_tags: &mut TagFilter<T::Discriminant>,
+) -> Result<(), String> {
+  match msg {
+    ControlMessage::Subscribe { tags } => {
+      let parsed: Vec<T::Discriminant> =
+        tags.iter().map(|tag| T::Discriminant::from_str(tag)).collect::<Result<_, _>>()?;
+      active_tags.subscribe(parsed);
+    }
+    ControlMessage::Unsubscribe { tags } => {
+      let parsed: Vec<T::Discriminant> =
+        tags.iter().map(|tag| T::Discriminant::from_str(tag)).collect::<Result<_, _>>()?;
+      active_tags.unsubscribe(&parsed);
+    }
+  }
+  Ok(())
+}
+
+/// Configures the bounded ring buffer of recent broadcast items kept
+/// around so a freshly connected client can replay history instead of
+/// only seeing items broadcast after it subscribed.
+#[derive(Clone, Copy)]
+pub struct ReplayConfig {
+  /// Max number of recent items retained for replay, per `T::Discriminant`
+  /// (a busy tag can't evict a rarely-broadcast one out of backfill range).
+  pub capacity: usize,
+}
+
+impl Default for ReplayConfig {
+  fn default() -> Self {
+    ReplayConfig { capacity: 256 }
+  }
+}
+
+/// A FIFO of the most recent broadcast items, kept separately per
+/// `T::Discriminant` and capped at `capacity` each, so a tag that's
+/// rarely broadcast isn't pushed out of backfill range by a busy one.
+/// Fed by a dedicated subscriber task so every client connection can
+/// read a consistent snapshot of it without itself tracking history.
+///
+/// Each item is stamped with a sequence number, monotonically
+/// increasing across all tags, so a replay spanning several tags can be
+/// merged back into the order the items actually arrived in.
+struct ReplayBuffer<T: SerializableWithDiscriminant> {
+  capacity: usize,
+  next_seq: u64,
+  buckets: HashMap<T::Discriminant, VecDeque<(u64, T)>>,
+}
+
+impl<T: SerializableWithDiscriminant> ReplayBuffer<T> {
+  fn new(capacity: usize) -> Self {
+    ReplayBuffer { capacity, next_seq: 0, buckets: HashMap::new() }
+  }
+
+  /// Pushes `item` into its discriminant's bucket, evicting that
+  /// bucket's oldest entry if it's already at `capacity`. Returns the
+  /// sequence number assigned to `item`.
+  fn push(&mut self, item: T) -> u64 {
+    let seq = self.next_seq;
+    self.next_seq += 1;
+    if self.capacity == 0 {
+      return seq;
+    }
+    let bucket = self.buckets.entry(item.clone().into()).or_insert_with(VecDeque::new);
+    if bucket.len() == self.capacity {
+      bucket.pop_front();
+    }
+    bucket.push_back((seq, item));
+    seq
+  }
+
+  /// The most recent `limit` items (oldest first) matching `tags`.
+  fn snapshot(&self, tags: &TagFilter<T::Discriminant>, limit: usize) -> Vec<(u64, T)> {
+    let mut matched: Vec<&(u64, T)> = match tags {
+      TagFilter::All => self.buckets.values().flatten().collect(),
+      TagFilter::Only(tags) => {
+        tags.iter().filter_map(|tag| self.buckets.get(tag)).flatten().collect()
+      }
+    };
+    matched.sort_by_key(|(seq, _)| *seq);
+    let skip = matched.len().saturating_sub(limit);
+    matched.into_iter().skip(skip).map(|(seq, item)| (*seq, item.clone())).collect()
+  }
+}
+
+/// Bundles the replay buffer with the broadcast channel used to hand
+/// connecting clients off from their replay snapshot to live delivery
+/// without a gap. Both are guarded by the same lock, so a client's
+/// "read the snapshot, then subscribe" sequence (in `client_connection`)
+/// is fully ordered against the feeder's "assign the next item a
+/// sequence number, store it, then publish it" sequence (below): each
+/// item is either entirely visible to the client before it subscribes
+/// (so it's in the snapshot and won't arrive again) or not yet
+/// published when it subscribes (so it can't be in the snapshot and
+/// will arrive live) — there's no window where it's neither.
+struct ReplayState<T: SerializableWithDiscriminant> {
+  buffer: ReplayBuffer<T>,
+  live_tx: broadcast::Sender<FeedEvent<T>>,
+}
+
+/// Published on `ReplayState::live_tx`: either a broadcast item (with
+/// its replay-buffer sequence number), or a marker that the feeder
+/// itself fell behind `ws_tx` and had to skip some events. The latter
+/// is surfaced to every connected client as a `ServerNotice::Lagged`,
+/// the same as lag on the client's own hop to `live_tx` — otherwise a
+/// feeder that falls behind would drop data for every client with no
+/// sign anything was missed.
+#[derive(Clone)]
+enum FeedEvent<T> {
+  Item(u64, T),
+  Lagged(u64),
+}
+
+/// Subscribes to `ws_tx` and, for each item, stores it in the replay
+/// buffer and republishes it on `state`'s internal `live_tx` — both
+/// under the same lock, which is what lets `client_connection` snapshot
+/// and subscribe without racing the feeder (see `ReplayState`). Runs
+/// independently of any client connection so the buffer stays populated
+/// even with zero clients.
+fn spawn_replay_feeder<T: 'static>(
+  ws_tx: &broadcast::Sender<T>,
+  state: Arc<Mutex<ReplayState<T>>>,
+) where
+  T: SerializableWithDiscriminant,
+{
+  let mut feed_rx = ws_tx.subscribe();
+  tokio::spawn(async move {
+    loop {
+      match feed_rx.recv().await {
+        Ok(event) => {
+          let mut state = state.lock().unwrap();
+          let seq = state.buffer.push(event.clone());
+          let _ = state.live_tx.send(FeedEvent::Item(seq, event));
+        }
+        Err(RecvError::Lagged(skipped)) => {
+          let _ = state.lock().unwrap().live_tx.send(FeedEvent::Lagged(skipped));
+        }
+        Err(RecvError::Closed) => break,
+      }
+    }
+  });
+}
+
+/// Where the websocket server accepts connections: a bound TCP address,
+/// a Unix domain socket path, or a TLS-terminated TCP endpoint.
+pub enum BindTarget {
+  Tcp(SocketAddr),
+  Uds(PathBuf),
+  Tls { addr: SocketAddr, cert_path: PathBuf, key_path: PathBuf },
 }
 
 /// Main function of the lib. This will spawn a tokio runtime and
-/// block in a task that contains a websocket server. 
-/// 
+/// block in a task that contains a websocket server.
+///
 /// This websocket server is responsible to share with all of
 /// its clients (broadcast) the enum item sended by the channel `ws_tx`.
-/// 
-/// This enum item must implement `SerializableWithDiscriminant` in 
+///
+/// This enum item must implement `SerializableWithDiscriminant` in
 /// order to be serde::Serializable, so the json encoding can be performed
 /// and in order to have `Discriminant` type, that is used to specify filtrable
 /// string tags for the clients.
-pub fn ws_loop<T: 'static>(port: u16, ws_tx: broadcast::Sender<T>)
-where
+///
+/// Besides the broadcast stream, each client may also issue requests
+/// over the same socket; these are dispatched to `handler` and answered
+/// directly to the originating client, bypassing tag filtering.
+///
+/// This is a thin wrapper over `ws_loop_with` that binds plain TCP on
+/// `127.0.0.1:port`, kept for backward compatibility.
+pub fn ws_loop<T: 'static, H: RequestHandler>(
+  port: u16,
+  ws_tx: broadcast::Sender<T>,
+  handler: H,
+  ctx: H::Ctx,
+) where
+  T: SerializableWithDiscriminant,
+{
+  ws_loop_with_send_buffer(port, ws_tx, handler, ctx, SendBufferConfig::default())
+}
+
+/// Same as `ws_loop`, but with the per-client send buffer sized
+/// explicitly instead of using `SendBufferConfig::default()`.
+pub fn ws_loop_with_send_buffer<T: 'static, H: RequestHandler>(
+  port: u16,
+  ws_tx: broadcast::Sender<T>,
+  handler: H,
+  ctx: H::Ctx,
+  send_buffer: SendBufferConfig,
+) where
+  T: SerializableWithDiscriminant,
+{
+  ws_loop_with(
+    BindTarget::Tcp(([127, 0, 0, 1], port).into()),
+    ws_tx,
+    handler,
+    ctx,
+    send_buffer,
+    ReplayConfig::default(),
+  )
+}
+
+/// Full entry point: like `ws_loop`, but lets the caller pick any
+/// `BindTarget` instead of always binding TCP on `127.0.0.1`, and size
+/// the replay buffer used for `?replay=N` backfill.
+pub fn ws_loop_with<T: 'static, H: RequestHandler>(
+  bind: BindTarget,
+  ws_tx: broadcast::Sender<T>,
+  handler: H,
+  ctx: H::Ctx,
+  send_buffer: SendBufferConfig,
+  replay: ReplayConfig,
+) where
   T: SerializableWithDiscriminant,
 {
   let runtime = tokio::runtime::Runtime::new().unwrap();
   runtime.block_on(async move {
-    ws_server(port, ws_tx).await;
+    ws_server(bind, ws_tx, Arc::new(handler), ctx, send_buffer, replay).await;
   });
 }
 
-async fn ws_server<T: 'static>(port: u16, ws_tx: broadcast::Sender<T>)
-where
+async fn ws_server<T: 'static, H: RequestHandler>(
+  bind: BindTarget,
+  ws_tx: broadcast::Sender<T>,
+  handler: Arc<H>,
+  ctx: H::Ctx,
+  send_buffer: SendBufferConfig,
+  replay: ReplayConfig,
+) where
   T: SerializableWithDiscriminant,
 {
+  // The internal live channel's capacity only needs to cover the gap
+  // between a broadcast landing in the replay buffer and every
+  // currently-connected client's `recv` being polled, so it's sized off
+  // the same knob as the buffer itself rather than adding another one.
+  let (live_tx, _) = broadcast::channel(replay.capacity.max(1));
+  let replay_state =
+    Arc::new(Mutex::new(ReplayState { buffer: ReplayBuffer::new(replay.capacity), live_tx }));
+  spawn_replay_feeder(&ws_tx, replay_state.clone());
+
   let ws_route = warp::ws()
-    .and(with_rx(ws_tx.clone()))
+    .and(with_handler(handler, ctx))
+    .and(with_send_buffer(send_buffer))
+    .and(with_replay_state(replay_state))
     .and(warp::query::<QueryParams>().map(parse_query))
     .and_then(ws_handler);
-  warp::serve(ws_route).run(([127, 0, 0, 1], port)).await;
+
+  match bind {
+    BindTarget::Tcp(addr) => {
+      warp::serve(ws_route).run(addr).await;
+    }
+    BindTarget::Tls { addr, cert_path, key_path } => {
+      warp::serve(ws_route).tls().cert_path(cert_path).key_path(key_path).run(addr).await;
+    }
+    BindTarget::Uds(path) => {
+      let listener =
+        tokio::net::UnixListener::bind(path).expect("failed to bind unix domain socket");
+      let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+      warp::serve(ws_route).run_incoming(incoming).await;
+    }
+  }
 }
 
 fn parse_query(query: QueryParams) -> Query {
@@ -63,64 +523,367 @@ fn parse_query(query: QueryParams) -> Query {
     Some(tags) => tags.split(',').map(str::to_string).collect(),
     None => vec![],
   };
-  Query { tags }
+  // An unrecognized encoding falls back to the default rather than
+  // rejecting the handshake, matching how an empty `tags` is treated.
+  let encoding = query
+    .encoding
+    .and_then(|encoding| Encoding::from_str(&encoding).ok())
+    .unwrap_or_default();
+  let replay = query.replay.unwrap_or(0);
+  Query { tags, encoding, replay }
 }
 
-fn with_rx<T>(
-  ws_tx: broadcast::Sender<T>,
-) -> impl Filter<Extract = (broadcast::Sender<T>,), Error = Infallible> + Clone
+fn with_handler<H: RequestHandler>(
+  handler: Arc<H>,
+  ctx: H::Ctx,
+) -> impl Filter<Extract = (Arc<H>, H::Ctx), Error = Infallible> + Clone {
+  warp::any().map(move || (handler.clone(), ctx.clone()))
+}
+
+fn with_send_buffer(
+  send_buffer: SendBufferConfig,
+) -> impl Filter<Extract = (SendBufferConfig,), Error = Infallible> + Clone {
+  warp::any().map(move || send_buffer)
+}
+
+fn with_replay_state<T: 'static>(
+  replay_state: Arc<Mutex<ReplayState<T>>>,
+) -> impl Filter<Extract = (Arc<Mutex<ReplayState<T>>>,), Error = Infallible> + Clone
 where
   T: SerializableWithDiscriminant,
 {
-  warp::any().map(move || ws_tx.clone())
+  warp::any().map(move || replay_state.clone())
 }
 
-async fn ws_handler<T: 'static>(
+async fn ws_handler<T: 'static, H: RequestHandler>(
   ws: warp::ws::Ws,
-  ws_tx: broadcast::Sender<T>,
+  (handler, ctx): (Arc<H>, H::Ctx),
+  send_buffer: SendBufferConfig,
+  replay_state: Arc<Mutex<ReplayState<T>>>,
   query: Query,
 ) -> Result<impl Reply, Rejection>
 where
   T: SerializableWithDiscriminant,
 {
-  Ok(ws.on_upgrade(move |socket| client_connection(socket, ws_tx, query.tags)))
+  Ok(ws.on_upgrade(move |socket| {
+    client_connection(
+      socket,
+      query.tags,
+      query.encoding,
+      query.replay,
+      handler,
+      ctx,
+      send_buffer,
+      replay_state,
+    )
+  }))
 }
 
-async fn client_connection<T>(
+/// How long to wait for the writer task to drain its outbox and close
+/// the socket on disconnect before giving up and aborting it outright,
+/// so a send stuck on a stalled client can't pin the task open forever.
+const WRITER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn client_connection<T, H: RequestHandler>(
   ws: WebSocket,
-  ws_tx: broadcast::Sender<T>,
   tags: Vec<String>,
+  encoding: Encoding,
+  replay: usize,
+  handler: Arc<H>,
+  ctx: H::Ctx,
+  send_buffer: SendBufferConfig,
+  replay_state: Arc<Mutex<ReplayState<T>>>,
 ) where
   T: SerializableWithDiscriminant,
 {
-  let (mut client_ws_sender, _) = ws.split();
-  let mut ws_rx = ws_tx.subscribe();
-  let mut count = 0;
-
-  while let Ok(event) = ws_rx.recv().await {
-    let tags: Result<Vec<T::Discriminant>, String> =
-      tags.iter().map(|tag| T::Discriminant::from_str(tag)).collect();
-
-    if let Ok(tags) = tags {
-      if tags.is_empty() || tags.contains(&(event.clone().into())) {
-        let json_stringfied = serde_json::to_string(&event).unwrap();
-        if let Err(err) =
-          client_ws_sender.send(Message::text(json_stringfied)).await
-        {
-          eprintln!("Could not send message through websocket: {}", err);
-          count += 1;
-        } else {
-          count = 0;
-        };
-        // After 10 consecutive fails we close the connection
-        if count == 10 {
+  let (client_ws_sender, mut client_ws_receiver) = ws.split();
+
+  let mut active_tags: TagFilter<T::Discriminant> = if tags.is_empty() {
+    TagFilter::All
+  } else {
+    match tags.iter().map(|tag| T::Discriminant::from_str(tag)).collect() {
+      Ok(tags) => TagFilter::Only(tags),
+      Err(_) => return,
+    }
+  };
+
+  // The writer task owns the socket's send half and drains the outbox at
+  // whatever pace the underlying connection allows; the rest of this
+  // function only ever enqueues onto it, so a stalled client never
+  // blocks the shared `broadcast::Sender` or other subscribers.
+  let (outbox_tx, mut outbox_rx) = mpsc::channel::<Message>(send_buffer.capacity);
+  let mut writer = tokio::spawn(async move {
+    let mut client_ws_sender = client_ws_sender;
+    while let Some(msg) = outbox_rx.recv().await {
+      if let Err(err) = client_ws_sender.send(msg).await {
+        eprintln!("Could not send message through websocket: {}", err);
+        break;
+      }
+    }
+  });
+
+  let mut full_count = 0;
+
+  // Reading the snapshot and subscribing to `live_tx` under the same
+  // lock the feeder uses to publish (see `ReplayState`) is what makes
+  // this boundary exact rather than best-effort: every item is either
+  // fully accounted for in the snapshot already, or still unpublished
+  // and therefore guaranteed to arrive on this subscription — there's
+  // no item that could land in neither or both.
+  let mut ws_rx = {
+    let state = replay_state.lock().unwrap();
+    let backlog =
+      if replay > 0 { state.buffer.snapshot(&active_tags, replay) } else { Vec::new() };
+    let ws_rx = state.live_tx.subscribe();
+    drop(state);
+
+    for (_, item) in backlog {
+      if let Ok(encoded) = encode_message(encoding, &item) {
+        if !enqueue(&outbox_tx, &mut full_count, send_buffer.max_full_polls, encoded) {
           break;
+        }
+      }
+    }
+    ws_rx
+  };
+
+  loop {
+    tokio::select! {
+      event = ws_rx.recv() => {
+        match event {
+          Ok(FeedEvent::Item(_, event)) => {
+            if active_tags.matches(&event.clone().into()) {
+              if let Ok(encoded) = encode_message(encoding, &event) {
+                if !enqueue(&outbox_tx, &mut full_count, send_buffer.max_full_polls, encoded) {
+                  break;
+                }
+              }
+            }
+          }
+          // The feeder itself fell behind `ws_tx`, before anything
+          // reached the replay buffer or `live_tx` at all.
+          Ok(FeedEvent::Lagged(skipped)) => {
+            let notice = ServerNotice::Lagged { skipped };
+            if let Ok(encoded) = encode_message(encoding, &notice) {
+              if !enqueue(&outbox_tx, &mut full_count, send_buffer.max_full_polls, encoded) {
+                break;
+              }
+            }
+          }
+          // This client's own hop from `live_tx` fell behind.
+          Err(RecvError::Lagged(skipped)) => {
+            let notice = ServerNotice::Lagged { skipped };
+            if let Ok(encoded) = encode_message(encoding, &notice) {
+              if !enqueue(&outbox_tx, &mut full_count, send_buffer.max_full_polls, encoded) {
+                break;
+              }
+            }
+            // Skip ahead to the current tail; the next `recv` resumes
+            // live delivery from there.
+            ws_rx = replay_state.lock().unwrap().live_tx.subscribe();
+          }
+          Err(RecvError::Closed) => break,
+        }
+      }
+
+      msg = client_ws_receiver.next() => {
+        let msg = match msg {
+          Some(Ok(msg)) => msg,
+          Some(Err(err)) => {
+            eprintln!("Error reading from websocket: {}", err);
+            break;
+          }
+          None => break,
         };
+
+        match try_control_message::<T>(&msg, &mut active_tags, encoding) {
+          Some(Ok(())) => continue,
+          Some(Err(message)) => {
+            let notice = ServerNotice::Error { message };
+            if let Ok(encoded) = encode_message(encoding, &notice) {
+              if !enqueue(&outbox_tx, &mut full_count, send_buffer.max_full_polls, encoded) {
+                break;
+              }
+            }
+            continue;
+          }
+          None => {}
+        }
+
+        if let Some(response) = handle_request(&*handler, ctx.clone(), msg, encoding).await {
+          if !enqueue(&outbox_tx, &mut full_count, send_buffer.max_full_polls, response) {
+            break;
+          }
+        }
       }
-    } else {
-      break;
     }
   }
 
+  drop(outbox_tx);
+  // The writer may be parked mid-`send` on a stalled or half-open
+  // socket, which dropping the outbox alone can't interrupt; bound how
+  // long we wait for it to finish before cutting it loose.
+  if tokio::time::timeout(WRITER_SHUTDOWN_TIMEOUT, &mut writer).await.is_err() {
+    writer.abort();
+  }
+
   eprintln!("Disconnected");
 }
+
+/// Attempts to read `msg` as a `ControlMessage` and apply it to
+/// `active_tags`. Returns `None` when `msg` isn't a control message at
+/// all, so the caller can fall through to request handling; otherwise
+/// returns the result of applying it.
+fn try_control_message<T: SerializableWithDiscriminant>(
+  msg: &Message,
+  active_tags: &mut TagFilter<T::Discriminant>,
+  encoding: Encoding,
+) -> Option<Result<(), String>> {
+  let control: ControlMessage = decode_message(encoding, msg)?;
+  Some(apply_control_message::<T>(control, active_tags))
+}
+
+/// Decodes a client frame under `encoding` as a `RequestEnvelope<H::Req>`, dispatches
+/// it to `handler`, and wraps the result back up with the same
+/// `request_id`. Frames that aren't a well-formed request (e.g. ping
+/// frames, or a payload that doesn't decode) are silently ignored rather than
+/// closing the connection.
+async fn handle_request<H: RequestHandler>(
+  handler: &H,
+  ctx: H::Ctx,
+  msg: Message,
+  encoding: Encoding,
+) -> Option<Message> {
+  let envelope: RequestEnvelope<H::Req> = decode_message(encoding, &msg)?;
+  let resp = handler.handle(ctx, envelope.req).await;
+  let response = ResponseEnvelope { request_id: envelope.request_id, resp };
+  encode_message(encoding, &response).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+  struct Sample {
+    id: u64,
+    label: String,
+  }
+
+  fn sample() -> Sample {
+    Sample { id: 42, label: "block".to_string() }
+  }
+
+  #[test]
+  fn json_round_trips() {
+    let encoded = encode_message(Encoding::Json, &sample()).unwrap();
+    assert!(encoded.is_text());
+    let decoded: Sample = decode_message(Encoding::Json, &encoded).unwrap();
+    assert_eq!(decoded, sample());
+  }
+
+  #[test]
+  fn msgpack_round_trips() {
+    let encoded = encode_message(Encoding::MessagePack, &sample()).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: Sample = decode_message(Encoding::MessagePack, &encoded).unwrap();
+    assert_eq!(decoded, sample());
+  }
+
+  #[test]
+  fn cbor_round_trips() {
+    let encoded = encode_message(Encoding::Cbor, &sample()).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: Sample = decode_message(Encoding::Cbor, &encoded).unwrap();
+    assert_eq!(decoded, sample());
+  }
+
+  #[test]
+  fn unknown_encoding_is_rejected() {
+    assert_eq!(Encoding::from_str("json"), Ok(Encoding::Json));
+    assert_eq!(Encoding::from_str("msgpack"), Ok(Encoding::MessagePack));
+    assert_eq!(Encoding::from_str("cbor"), Ok(Encoding::Cbor));
+    assert!(Encoding::from_str("bogus").is_err());
+  }
+
+  // `RequestEnvelope`/`ResponseEnvelope` use `#[serde(flatten)]` and
+  // `ControlMessage`/`ServerNotice` are internally tagged — both forms
+  // serialize through a map of unknown length, which is exactly what
+  // trips up length-prefixed binary formats like MessagePack. These
+  // round-trip the wire types themselves under every binary encoding,
+  // not just a plain struct, to catch that class of failure.
+
+  #[test]
+  fn request_envelope_round_trips_under_msgpack() {
+    let req = RequestEnvelope { request_id: "req-1".to_string(), req: sample() };
+    let encoded = encode_message(Encoding::MessagePack, &req).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: RequestEnvelope<Sample> =
+      decode_message(Encoding::MessagePack, &encoded).unwrap();
+    assert_eq!(decoded, req);
+  }
+
+  #[test]
+  fn request_envelope_round_trips_under_cbor() {
+    let req = RequestEnvelope { request_id: "req-1".to_string(), req: sample() };
+    let encoded = encode_message(Encoding::Cbor, &req).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: RequestEnvelope<Sample> = decode_message(Encoding::Cbor, &encoded).unwrap();
+    assert_eq!(decoded, req);
+  }
+
+  #[test]
+  fn response_envelope_round_trips_under_msgpack() {
+    let resp = ResponseEnvelope { request_id: "req-2".to_string(), resp: sample() };
+    let encoded = encode_message(Encoding::MessagePack, &resp).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: ResponseEnvelope<Sample> =
+      decode_message(Encoding::MessagePack, &encoded).unwrap();
+    assert_eq!(decoded, resp);
+  }
+
+  #[test]
+  fn response_envelope_round_trips_under_cbor() {
+    let resp = ResponseEnvelope { request_id: "req-2".to_string(), resp: sample() };
+    let encoded = encode_message(Encoding::Cbor, &resp).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: ResponseEnvelope<Sample> = decode_message(Encoding::Cbor, &encoded).unwrap();
+    assert_eq!(decoded, resp);
+  }
+
+  #[test]
+  fn control_message_round_trips_under_msgpack() {
+    let msg = ControlMessage::Subscribe { tags: vec!["block".to_string()] };
+    let encoded = encode_message(Encoding::MessagePack, &msg).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: ControlMessage = decode_message(Encoding::MessagePack, &encoded).unwrap();
+    assert_eq!(decoded, msg);
+  }
+
+  #[test]
+  fn control_message_round_trips_under_cbor() {
+    let msg = ControlMessage::Unsubscribe { tags: vec!["block".to_string()] };
+    let encoded = encode_message(Encoding::Cbor, &msg).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: ControlMessage = decode_message(Encoding::Cbor, &encoded).unwrap();
+    assert_eq!(decoded, msg);
+  }
+
+  #[test]
+  fn server_notice_round_trips_under_msgpack() {
+    let notice = ServerNotice::Lagged { skipped: 7 };
+    let encoded = encode_message(Encoding::MessagePack, &notice).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: ServerNotice = decode_message(Encoding::MessagePack, &encoded).unwrap();
+    assert_eq!(decoded, notice);
+  }
+
+  #[test]
+  fn server_notice_round_trips_under_cbor() {
+    let notice = ServerNotice::Error { message: "boom".to_string() };
+    let encoded = encode_message(Encoding::Cbor, &notice).unwrap();
+    assert!(encoded.is_binary());
+    let decoded: ServerNotice = decode_message(Encoding::Cbor, &encoded).unwrap();
+    assert_eq!(decoded, notice);
+  }
+}